@@ -0,0 +1,309 @@
+use libp2p::identity::Keypair;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The key algorithm to generate a new identity with. Existing key files
+/// carry their own type tag, so this only matters the first time a node
+/// runs with a given `--identity` path.
+#[derive(Debug, Clone, Copy)]
+pub enum KeyType {
+    Ed25519,
+    Secp256k1,
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            other => Err(format!(
+                "unknown key type {:?}, expected \"ed25519\" or \"secp256k1\"",
+                other
+            )),
+        }
+    }
+}
+
+impl KeyType {
+    fn tag(self) -> u8 {
+        match self {
+            KeyType::Ed25519 => 0,
+            KeyType::Secp256k1 => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, Error> {
+        match tag {
+            0 => Ok(KeyType::Ed25519),
+            1 => Ok(KeyType::Secp256k1),
+            tag => Err(Error::Corrupt(format!("unknown key type tag {}", tag))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(std::io::Error),
+    Decoding(libp2p::identity::DecodingError),
+    /// The key file was truncated, had an unrecognized header, or failed to
+    /// decrypt.
+    Corrupt(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "I/O error: {}", e),
+            Error::Decoding(e) => write!(f, "key decoding error: {}", e),
+            Error::Corrupt(msg) => write!(f, "corrupt key file: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Error {
+        Error::IO(error)
+    }
+}
+
+impl From<libp2p::identity::DecodingError> for Error {
+    fn from(error: libp2p::identity::DecodingError) -> Error {
+        Error::Decoding(error)
+    }
+}
+
+/// Creates a new key file readable and writable only by its owner. On Unix
+/// the mode is applied at creation time via `OpenOptions`, so there's no
+/// window where the file briefly exists with the default (often
+/// world-readable) permissions. This is a plain `File::create` on non-Unix
+/// platforms, which have no equivalent file mode bits.
+fn create_private_file(path: &Path) -> std::io::Result<std::fs::File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+    }
+    #[cfg(not(unix))]
+    {
+        std::fs::File::create(path)
+    }
+}
+
+/// Derives a 32-byte symmetric key from a password by hashing it. This
+/// protects against the key file being read off disk by something that
+/// isn't also watching for the password; it is not a substitute for a
+/// proper password-based KDF if the password itself is weak.
+fn derive_key(password: &str) -> [u8; 32] {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(password.as_bytes());
+    hasher.finalize().into()
+}
+
+fn encrypt(password: &str, plaintext: &[u8]) -> Vec<u8> {
+    use chacha20poly1305::aead::{Aead, KeyInit};
+    use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+    use rand::RngCore;
+
+    let key = derive_key(password);
+    let cipher = XChaCha20Poly1305::new((&key).into());
+    let mut nonce_bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    let nonce = XNonce::from_slice(&nonce_bytes);
+    let ciphertext = cipher
+        .encrypt(nonce, plaintext)
+        .expect("encrypting the identity key should never fail");
+    [&nonce_bytes[..], &ciphertext[..]].concat()
+}
+
+fn decrypt(password: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+    use chacha20poly1305::aead::{Aead, KeyInit};
+    use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+    if data.len() < 24 {
+        return Err(Error::Corrupt("encrypted key file is too short".into()));
+    }
+    let (nonce_bytes, ciphertext) = data.split_at(24);
+    let key = derive_key(password);
+    let cipher = XChaCha20Poly1305::new((&key).into());
+    cipher
+        .decrypt(XNonce::from_slice(nonce_bytes), ciphertext)
+        .map_err(|_| Error::Corrupt("wrong password or corrupt key file".into()))
+}
+
+/// Loads the identity at `path`, generating and persisting a new one of
+/// `key_type` if it doesn't exist yet. If `password` is set, the key
+/// material is encrypted at rest with it.
+///
+/// The on-disk format is `[type tag: 1 byte][encrypted: 1 byte][payload]`,
+/// where payload is either the raw key bytes or, when encrypted, a 24-byte
+/// nonce followed by the ciphertext. This lets old files be distinguished
+/// from new ones and lets `load` reject a short read instead of silently
+/// decoding a corrupt key.
+pub fn load_or_generate(
+    path: &Path,
+    key_type: KeyType,
+    password: Option<&str>,
+) -> Result<Keypair, Error> {
+    if !path.exists() {
+        let (keypair, key_bytes) = generate(key_type);
+        let payload = match password {
+            Some(password) => encrypt(password, &key_bytes),
+            None => key_bytes,
+        };
+        let mut file = create_private_file(path)?;
+        file.write_all(&[key_type.tag(), password.is_some() as u8])?;
+        file.write_all(&payload)?;
+        Ok(keypair)
+    } else {
+        let mut file = std::fs::File::open(path)?;
+        let mut header = [0; 2];
+        file.read_exact(&mut header)
+            .map_err(|_| Error::Corrupt("key file is missing its header".into()))?;
+        let key_type = KeyType::from_tag(header[0])?;
+        let encrypted = header[1] != 0;
+
+        let mut payload = Vec::new();
+        file.read_to_end(&mut payload)?;
+
+        let key_bytes = if encrypted {
+            let password = password.ok_or_else(|| {
+                Error::Corrupt("key file is encrypted but no --key-password was given".into())
+            })?;
+            decrypt(password, &payload)?
+        } else {
+            payload
+        };
+
+        decode(key_type, &key_bytes)
+    }
+}
+
+fn generate(key_type: KeyType) -> (Keypair, Vec<u8>) {
+    match key_type {
+        KeyType::Ed25519 => {
+            let keypair = libp2p::identity::ed25519::Keypair::generate();
+            let bytes = keypair.to_bytes().to_vec();
+            (Keypair::from(keypair), bytes)
+        }
+        KeyType::Secp256k1 => {
+            let keypair = libp2p::identity::secp256k1::Keypair::generate();
+            let bytes = keypair.secret().to_bytes().to_vec();
+            (Keypair::from(keypair), bytes)
+        }
+    }
+}
+
+fn decode(key_type: KeyType, key_bytes: &[u8]) -> Result<Keypair, Error> {
+    match key_type {
+        KeyType::Ed25519 => {
+            if key_bytes.len() != 64 {
+                return Err(Error::Corrupt(format!(
+                    "expected a 64-byte ed25519 key, got {} bytes",
+                    key_bytes.len()
+                )));
+            }
+            let mut buf = [0; 64];
+            buf.copy_from_slice(key_bytes);
+            Ok(Keypair::from(libp2p::identity::ed25519::Keypair::try_from_bytes(
+                &mut buf,
+            )?))
+        }
+        KeyType::Secp256k1 => {
+            if key_bytes.len() != 32 {
+                return Err(Error::Corrupt(format!(
+                    "expected a 32-byte secp256k1 key, got {} bytes",
+                    key_bytes.len()
+                )));
+            }
+            let mut buf = [0; 32];
+            buf.copy_from_slice(key_bytes);
+            let secret = libp2p::identity::secp256k1::SecretKey::try_from_bytes(&mut buf)?;
+            Ok(Keypair::from(libp2p::identity::secp256k1::Keypair::from(
+                secret,
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path under the system temp dir, unique per test run, cleaned up on
+    /// drop so repeated test runs don't trip over a stale key file.
+    struct TempPath(std::path::PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            TempPath(std::env::temp_dir().join(format!(
+                "p2p-file-transfer-identity-test-{}-{}",
+                std::process::id(),
+                name
+            )))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn generates_and_reloads_the_same_identity() {
+        let path = TempPath::new("plain");
+        let first = load_or_generate(&path.0, KeyType::Ed25519, None).unwrap();
+        let second = load_or_generate(&path.0, KeyType::Ed25519, None).unwrap();
+        assert_eq!(first.public(), second.public());
+    }
+
+    #[test]
+    fn generates_and_reloads_a_secp256k1_identity() {
+        let path = TempPath::new("secp256k1");
+        let first = load_or_generate(&path.0, KeyType::Secp256k1, None).unwrap();
+        let second = load_or_generate(&path.0, KeyType::Secp256k1, None).unwrap();
+        assert_eq!(first.public(), second.public());
+    }
+
+    #[test]
+    fn encrypted_identity_round_trips_with_the_right_password() {
+        let path = TempPath::new("encrypted");
+        let first = load_or_generate(&path.0, KeyType::Ed25519, Some("hunter2")).unwrap();
+        let second = load_or_generate(&path.0, KeyType::Ed25519, Some("hunter2")).unwrap();
+        assert_eq!(first.public(), second.public());
+    }
+
+    #[test]
+    fn encrypted_identity_rejects_the_wrong_password() {
+        let path = TempPath::new("wrong-password");
+        load_or_generate(&path.0, KeyType::Ed25519, Some("hunter2")).unwrap();
+        let result = load_or_generate(&path.0, KeyType::Ed25519, Some("not-it"));
+        assert!(matches!(result, Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn rejects_a_truncated_key_file() {
+        let path = TempPath::new("truncated");
+        std::fs::write(&path.0, [0u8]).unwrap();
+        let result = load_or_generate(&path.0, KeyType::Ed25519, None);
+        assert!(matches!(result, Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn rejects_an_unknown_key_type_tag() {
+        let path = TempPath::new("bad-tag");
+        std::fs::write(&path.0, [0xffu8, 0]).unwrap();
+        let result = load_or_generate(&path.0, KeyType::Ed25519, None);
+        assert!(matches!(result, Err(Error::Corrupt(_))));
+    }
+}
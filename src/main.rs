@@ -1,11 +1,25 @@
+mod identity;
+mod transfer;
+mod transport;
+
 use futures::future;
 use libp2p::futures::StreamExt;
 use libp2p::identity::Keypair;
+use libp2p::request_response::{
+    Behaviour as RequestResponse, Config as RequestResponseConfig, Event as RequestResponseEvent,
+    Message as RequestResponseMessage, ProtocolSupport,
+};
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
 use libp2p::{Multiaddr, PeerId, Swarm};
-use log::{info, warn};
-use std::path::Path;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::iter;
+use std::path::{Path, PathBuf};
 use std::task::Poll;
+use std::time::Duration;
 use structopt::StructOpt;
+use transfer::{FileExchangeCodec, FileExchangeProtocol, FileRequest, FileResponse, MAX_CHUNK_SIZE};
 
 #[derive(StructOpt)]
 struct Options {
@@ -16,6 +30,17 @@ struct Options {
         default_value = "p2p.id"
     )]
     keypair: String,
+    #[structopt(
+        long = "key-type",
+        about = "The key algorithm to generate a new identity with (ed25519 or secp256k1)",
+        default_value = "ed25519"
+    )]
+    key_type: identity::KeyType,
+    #[structopt(
+        long = "key-password",
+        about = "Password to encrypt/decrypt the identity file at rest"
+    )]
+    key_password: Option<String>,
     #[structopt(
         short = "p",
         long = "peers",
@@ -30,16 +55,108 @@ struct Options {
         default_value = "/ip4/0.0.0.0/tcp/0"
     )]
     listen: Multiaddr,
+    #[structopt(
+        long = "relay",
+        about = "A relay multiaddr to use for reaching peers behind a NAT"
+    )]
+    relay: Option<Multiaddr>,
+    #[structopt(
+        long = "relay-server",
+        about = "Act as a relay for other peers in addition to our own traffic"
+    )]
+    relay_server: bool,
+    #[structopt(
+        long = "no-mdns",
+        about = "Disable automatic discovery of peers on the local network via mDNS"
+    )]
+    no_mdns: bool,
+    #[structopt(
+        long = "rendezvous",
+        about = "A rendezvous point multiaddr to register with and discover peers through"
+    )]
+    rendezvous: Option<Multiaddr>,
+    #[structopt(
+        long = "rendezvous-server",
+        about = "Act as a rendezvous point for other peers to register and discover each other"
+    )]
+    rendezvous_server: bool,
+    #[structopt(
+        long = "namespace",
+        about = "The rendezvous namespace to register and discover peers under",
+        default_value = "p2p-file-transfer"
+    )]
+    namespace: String,
+    #[structopt(long = "quic", about = "Also accept and dial QUIC connections")]
+    quic: bool,
+    #[structopt(long = "ws", about = "Also accept and dial WebSocket connections")]
+    ws: bool,
+    #[structopt(
+        long = "max-connections",
+        about = "Maximum number of connections established at once",
+        default_value = "100"
+    )]
+    max_connections: u32,
+    #[structopt(
+        long = "max-connections-per-peer",
+        about = "Maximum number of connections established with a single peer",
+        default_value = "8"
+    )]
+    max_connections_per_peer: u32,
+    #[structopt(
+        long = "max-pending",
+        about = "Maximum number of incoming and outgoing connections being dialed/negotiated at once",
+        default_value = "32"
+    )]
+    max_pending: u32,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Fetch a file from a peer and write it to disk.
+    Get {
+        /// The peer ID to fetch the file from.
+        peer: PeerId,
+        /// The path or content hash identifying the file on the remote peer.
+        file: String,
+    },
+    /// Serve files out of a directory to any peer that requests them.
+    Serve {
+        #[structopt(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Fetch a file by content hash, locating a provider via the Kademlia
+    /// DHT instead of requiring a known peer.
+    Fetch {
+        /// The hex-encoded SHA-256 hash of the file to fetch.
+        hash: String,
+    },
 }
 
 #[derive(Debug)]
 enum Error {
     IO(std::io::Error),
-    Decoding(libp2p::identity::error::DecodingError),
+    Identity(identity::Error),
     Multiaddr(libp2p::multiaddr::Error),
     Transport(libp2p::TransportError<std::io::Error>),
+    InvalidNamespace(String),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "I/O error: {}", e),
+            Error::Identity(e) => write!(f, "identity error: {:?}", e),
+            Error::Multiaddr(e) => write!(f, "invalid multiaddr: {}", e),
+            Error::Transport(e) => write!(f, "transport error: {}", e),
+            Error::InvalidNamespace(msg) => write!(f, "invalid namespace: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Error {
         Error::IO(error)
@@ -58,27 +175,19 @@ impl From<libp2p::TransportError<std::io::Error>> for Error {
     }
 }
 
-impl From<libp2p::identity::error::DecodingError> for Error {
-    fn from(error: libp2p::identity::error::DecodingError) -> Error {
-        Error::Decoding(error)
+impl From<identity::Error> for Error {
+    fn from(error: identity::Error) -> Error {
+        Error::Identity(error)
     }
 }
 
 fn keypair(options: &Options) -> Result<Keypair, Error> {
     let keypair_path = Path::new(&options.keypair);
-    if !keypair_path.exists() {
-        use std::io::Write;
-        let new_keypair = libp2p::identity::ed25519::Keypair::generate();
-        std::fs::File::create(keypair_path)?.write(&new_keypair.encode())?;
-        Ok(Keypair::Ed25519(new_keypair))
-    } else {
-        use std::io::Read;
-        let mut keypair = [0; 64];
-        std::fs::File::open(keypair_path)?.read(&mut keypair)?;
-        Ok(Keypair::Ed25519(
-            libp2p::identity::ed25519::Keypair::decode(&mut keypair)?,
-        ))
-    }
+    Ok(identity::load_or_generate(
+        keypair_path,
+        options.key_type,
+        options.key_password.as_deref(),
+    )?)
 }
 
 fn peers(options: &Options) -> Result<Vec<Multiaddr>, Error> {
@@ -93,12 +202,278 @@ fn peers(options: &Options) -> Result<Vec<Multiaddr>, Error> {
     }
 }
 
+/// Pulls the `/p2p/<peer-id>` component out of a multiaddr, if present.
+/// Rendezvous registration and discovery both need the rendezvous point's
+/// `PeerId`, not just its address.
+fn peer_id_from_multiaddr(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|protocol| match protocol {
+        libp2p::multiaddr::Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    })
+}
+
+/// Reads additional relay multiaddrs from `p2p.relays`, one per line, in
+/// the same format as `peers()`. These are dialed and `listen_on`'d as
+/// `/p2p-circuit` addresses if we turn out not to be publicly reachable.
+fn relays() -> Result<Vec<Multiaddr>, Error> {
+    let relays_path = Path::new("p2p.relays");
+    if relays_path.exists() {
+        use std::io::Read;
+        let mut relays = String::new();
+        std::fs::File::open(relays_path)?.read_to_string(&mut relays)?;
+        Ok(relays
+            .lines()
+            .filter_map(|line| line.parse().ok())
+            .collect())
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Hex-encoded SHA-256 of a file's contents, used as its Kademlia provider
+/// key and as the `path_or_hash` a peer sends in a `FileRequest`.
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Hashes every file directly inside `dir` and returns a map from hash to
+/// path, so that served files can be looked up by content hash rather than
+/// by name.
+fn hash_served_dir(dir: &Path) -> std::io::Result<HashMap<String, PathBuf>> {
+    let mut index = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            let path = entry.path();
+            match hash_file(&path) {
+                Ok(hash) => {
+                    index.insert(hash, path);
+                }
+                Err(e) => warn!("Couldn't hash {:?}: {:?}", path, e),
+            }
+        }
+    }
+    Ok(index)
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "BehaviourEvent")]
+struct Behaviour {
+    ping: libp2p::ping::Behaviour,
+    file_transfer: RequestResponse<FileExchangeCodec>,
+    autonat: libp2p::autonat::Behaviour,
+    relay_client: libp2p::relay::client::Behaviour,
+    relay_server: Toggle<libp2p::relay::Behaviour>,
+    dcutr: libp2p::dcutr::Behaviour,
+    mdns: Toggle<libp2p::mdns::tokio::Behaviour>,
+    rendezvous_client: Toggle<libp2p::rendezvous::client::Behaviour>,
+    rendezvous_server: Toggle<libp2p::rendezvous::server::Behaviour>,
+    kad: libp2p::kad::Kademlia<libp2p::kad::store::MemoryStore>,
+    connection_limits: libp2p::connection_limits::Behaviour,
+    // `ping::Config::with_keep_alive` is deprecated in favour of composing
+    // this in directly; it keeps connections alive the same way the old
+    // per-protocol flag did.
+    keep_alive: libp2p::swarm::keep_alive::Behaviour,
+}
+
+#[derive(Debug)]
+enum BehaviourEvent {
+    Ping(libp2p::ping::Event),
+    FileTransfer(RequestResponseEvent<FileRequest, FileResponse>),
+    Autonat(libp2p::autonat::Event),
+    RelayClient(libp2p::relay::client::Event),
+    RelayServer(libp2p::relay::Event),
+    Dcutr(libp2p::dcutr::Event),
+    Mdns(libp2p::mdns::Event),
+    RendezvousClient(libp2p::rendezvous::client::Event),
+    RendezvousServer(libp2p::rendezvous::server::Event),
+    Kad(libp2p::kad::KademliaEvent),
+    // `connection_limits::Behaviour` and `keep_alive::Behaviour` both only
+    // ever produce the uninhabited `void::Void`, so they share this variant.
+    Void(void::Void),
+}
+
+impl From<libp2p::ping::Event> for BehaviourEvent {
+    fn from(event: libp2p::ping::Event) -> Self {
+        BehaviourEvent::Ping(event)
+    }
+}
+
+impl From<RequestResponseEvent<FileRequest, FileResponse>> for BehaviourEvent {
+    fn from(event: RequestResponseEvent<FileRequest, FileResponse>) -> Self {
+        BehaviourEvent::FileTransfer(event)
+    }
+}
+
+impl From<libp2p::autonat::Event> for BehaviourEvent {
+    fn from(event: libp2p::autonat::Event) -> Self {
+        BehaviourEvent::Autonat(event)
+    }
+}
+
+impl From<libp2p::relay::client::Event> for BehaviourEvent {
+    fn from(event: libp2p::relay::client::Event) -> Self {
+        BehaviourEvent::RelayClient(event)
+    }
+}
+
+impl From<libp2p::relay::Event> for BehaviourEvent {
+    fn from(event: libp2p::relay::Event) -> Self {
+        BehaviourEvent::RelayServer(event)
+    }
+}
+
+impl From<libp2p::dcutr::Event> for BehaviourEvent {
+    fn from(event: libp2p::dcutr::Event) -> Self {
+        BehaviourEvent::Dcutr(event)
+    }
+}
+
+impl From<libp2p::mdns::Event> for BehaviourEvent {
+    fn from(event: libp2p::mdns::Event) -> Self {
+        BehaviourEvent::Mdns(event)
+    }
+}
+
+impl From<libp2p::rendezvous::client::Event> for BehaviourEvent {
+    fn from(event: libp2p::rendezvous::client::Event) -> Self {
+        BehaviourEvent::RendezvousClient(event)
+    }
+}
+
+impl From<libp2p::rendezvous::server::Event> for BehaviourEvent {
+    fn from(event: libp2p::rendezvous::server::Event) -> Self {
+        BehaviourEvent::RendezvousServer(event)
+    }
+}
+
+impl From<libp2p::kad::KademliaEvent> for BehaviourEvent {
+    fn from(event: libp2p::kad::KademliaEvent) -> Self {
+        BehaviourEvent::Kad(event)
+    }
+}
+
+impl From<void::Void> for BehaviourEvent {
+    fn from(event: void::Void) -> Self {
+        BehaviourEvent::Void(event)
+    }
+}
+
+/// Tracks the in-progress `get` download, if any: where we're writing the
+/// file and how far we've read so far.
+struct Download {
+    file: String,
+    out: std::fs::File,
+    offset: u64,
+}
+
+/// Sends the first chunk request for `file` to `peer` and starts tracking
+/// the download, shared by the direct `get` and DHT-resolved `fetch` paths.
+fn start_download(
+    swarm: &mut Swarm<Behaviour>,
+    pending_requests: &mut HashMap<libp2p::request_response::RequestId, ()>,
+    download: &mut Option<Download>,
+    peer: &PeerId,
+    file: &str,
+) {
+    let out = match std::fs::File::create(file) {
+        Ok(f) => f,
+        Err(e) => {
+            warn!("Couldn't create output file {}: {:?}", file, e);
+            return;
+        }
+    };
+    let request_id = swarm.behaviour_mut().file_transfer.send_request(
+        peer,
+        FileRequest {
+            path_or_hash: file.to_string(),
+            offset: 0,
+        },
+    );
+    pending_requests.insert(request_id, ());
+    *download = Some(Download {
+        file: file.to_string(),
+        out,
+        offset: 0,
+    });
+}
+
+/// Serves a single chunk of `path_or_hash` starting at `offset` out of
+/// `serve_dir`, answering with `NotFound` if the file doesn't exist and
+/// `Done` once the offset has reached the end of the file. `hash_index`
+/// additionally maps content hashes (as served by the Kademlia DHT) to
+/// their path, so a request can name either.
+///
+/// `path_or_hash` comes straight off the wire from an untrusted peer, so we
+/// never trust it to stay inside `serve_dir`: anything containing a `..`
+/// component or rooted outside of it (an absolute path replaces the base
+/// entirely under `Path::join`) is rejected before we touch the filesystem.
+fn read_chunk(
+    serve_dir: &Path,
+    hash_index: &HashMap<String, PathBuf>,
+    path_or_hash: &str,
+    offset: u64,
+) -> FileResponse {
+    use std::io::{Read, Seek, SeekFrom};
+    use std::path::Component;
+
+    let path = match hash_index.get(path_or_hash) {
+        Some(path) => path.clone(),
+        None => {
+            if Path::new(path_or_hash)
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+            {
+                warn!("Rejecting unsafe file request for {:?}", path_or_hash);
+                return FileResponse::NotFound;
+            }
+            serve_dir.join(path_or_hash)
+        }
+    };
+    let mut file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Couldn't open {:?} to serve: {:?}", path, e);
+            return FileResponse::NotFound;
+        }
+    };
+    if file.seek(SeekFrom::Start(offset)).is_err() {
+        return FileResponse::Done;
+    }
+    let mut buf = vec![0; MAX_CHUNK_SIZE];
+    match file.read(&mut buf) {
+        Ok(0) => FileResponse::Done,
+        Ok(n) => {
+            buf.truncate(n);
+            FileResponse::Chunk(buf)
+        }
+        Err(e) => {
+            warn!("Error reading {:?}: {:?}", path, e);
+            FileResponse::NotFound
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     env_logger::init();
     let options = Options::from_args();
     let keypair = keypair(&options)?;
     let peer_id = PeerId::from(keypair.public());
+    let rendezvous_keypair = keypair.clone();
 
     info!("My peer ID: {:?}", peer_id);
 
@@ -106,23 +481,352 @@ async fn main() -> Result<(), Error> {
 
     info!("Connecting to peers: {:?}", peers);
 
-    let transport = libp2p::development_transport(keypair).await?;
+    let (relay_transport, relay_client) = libp2p::relay::client::new(peer_id);
+
+    let (transport, bandwidth_sinks) =
+        transport::build(&keypair, relay_transport, options.quic, options.ws).await?;
 
-    let behavior = libp2p::ping::Ping::new(libp2p::ping::PingConfig::new().with_keep_alive(true));
+    let behaviour = Behaviour {
+        ping: libp2p::ping::Behaviour::new(libp2p::ping::Config::new()),
+        file_transfer: RequestResponse::new(
+            FileExchangeCodec::default(),
+            iter::once((FileExchangeProtocol(), ProtocolSupport::Full)),
+            RequestResponseConfig::default(),
+        ),
+        autonat: libp2p::autonat::Behaviour::new(peer_id, libp2p::autonat::Config::default()),
+        relay_client,
+        relay_server: Toggle::from(options.relay_server.then(|| {
+            libp2p::relay::Behaviour::new(peer_id, libp2p::relay::Config::default())
+        })),
+        dcutr: libp2p::dcutr::Behaviour::new(peer_id),
+        mdns: Toggle::from(if options.no_mdns {
+            None
+        } else {
+            Some(libp2p::mdns::tokio::Behaviour::new(
+                libp2p::mdns::Config::default(),
+                peer_id,
+            )?)
+        }),
+        rendezvous_client: Toggle::from(
+            options
+                .rendezvous
+                .as_ref()
+                .map(|_| libp2p::rendezvous::client::Behaviour::new(rendezvous_keypair)),
+        ),
+        rendezvous_server: Toggle::from(options.rendezvous_server.then(|| {
+            libp2p::rendezvous::server::Behaviour::new(libp2p::rendezvous::server::Config::default())
+        })),
+        kad: {
+            let mut kad = libp2p::kad::Kademlia::new(
+                peer_id,
+                libp2p::kad::store::MemoryStore::new(peer_id),
+            );
+            for addr in &peers {
+                if let Some(bootstrap_peer) = peer_id_from_multiaddr(addr) {
+                    kad.add_address(&bootstrap_peer, addr.clone());
+                }
+            }
+            kad
+        },
+        connection_limits: libp2p::connection_limits::Behaviour::new(
+            libp2p::connection_limits::ConnectionLimits::default()
+                .with_max_established(Some(options.max_connections))
+                .with_max_established_per_peer(Some(options.max_connections_per_peer))
+                .with_max_pending_incoming(Some(options.max_pending))
+                .with_max_pending_outgoing(Some(options.max_pending)),
+        ),
+        keep_alive: libp2p::swarm::keep_alive::Behaviour,
+    };
 
-    let mut swarm = Swarm::new(transport, behavior, peer_id);
+    let mut swarm =
+        libp2p::swarm::SwarmBuilder::with_tokio_executor(transport, behaviour, peer_id).build();
 
     swarm.listen_on(options.listen)?;
 
-    for addr in peers {
+    let have_bootstrap_peers = !peers.is_empty();
+    for addr in peers.into_iter().chain(relays()?) {
         match swarm.dial(addr.clone()) {
             Ok(()) => info!("Connected to {:?}", addr),
             Err(e) => warn!("Couldn't connect to {:?} with error {:?}", addr, e),
         }
     }
 
+    if let Some(relay) = &options.relay {
+        match swarm.dial(relay.clone()) {
+            Ok(()) => info!("Dialing relay {:?}", relay),
+            Err(e) => warn!("Couldn't dial relay {:?}: {:?}", relay, e),
+        }
+    }
+
+    let rendezvous_peer_id = options.rendezvous.as_ref().and_then(peer_id_from_multiaddr);
+    if let Some(rendezvous) = &options.rendezvous {
+        match swarm.dial(rendezvous.clone()) {
+            Ok(()) => info!("Dialing rendezvous point {:?}", rendezvous),
+            Err(e) => warn!("Couldn't dial rendezvous point {:?}: {:?}", rendezvous, e),
+        }
+    }
+    let namespace = libp2p::rendezvous::Namespace::new(options.namespace.clone())
+        .map_err(|e| Error::InvalidNamespace(format!("{:?}", e)))?;
+    let mut rendezvous_cookie: Option<libp2p::rendezvous::Cookie> = None;
+    let mut discover_tick = tokio::time::interval(Duration::from_secs(30));
+    let mut bandwidth_tick = tokio::time::interval(Duration::from_secs(60));
+
+    let mut pending_requests: HashMap<libp2p::request_response::RequestId, ()> = HashMap::new();
+    let mut download: Option<Download> = None;
+    let mut hash_index: HashMap<String, PathBuf> = HashMap::new();
+    // Set once `get_providers` for a `Fetch` resolves, so the request can be
+    // sent as soon as we connect to the chosen provider.
+    let mut fetch_target: Option<(PeerId, String)> = None;
+
+    if have_bootstrap_peers {
+        swarm.behaviour_mut().kad.bootstrap().ok();
+    }
+
+    match &options.command {
+        Command::Get { peer, file } => {
+            if let Err(e) = swarm.dial(*peer) {
+                warn!("Couldn't dial {:?}: {:?}", peer, e);
+            }
+            // `dial` is a no-op on an already-connected peer (e.g. one we
+            // already reached via the `peers()` list above), in which case
+            // `ConnectionEstablished` won't fire again to kick off the
+            // download, so start it right away if we're already connected.
+            if swarm.is_connected(peer) {
+                start_download(&mut swarm, &mut pending_requests, &mut download, peer, file);
+            }
+        }
+        Command::Serve { dir } => {
+            hash_index = hash_served_dir(dir)?;
+            for hash in hash_index.keys() {
+                let key = libp2p::kad::RecordKey::new(&hash.as_bytes());
+                if let Err(e) = swarm.behaviour_mut().kad.start_providing(key) {
+                    warn!("Couldn't advertise {} as provided: {:?}", hash, e);
+                }
+            }
+            info!("Serving {} files out of {:?}", hash_index.len(), dir);
+        }
+        Command::Fetch { hash } => {
+            let key = libp2p::kad::RecordKey::new(&hash.as_bytes());
+            swarm.behaviour_mut().kad.get_providers(key);
+        }
+    }
+
     future::poll_fn(move |cx| loop {
+        if discover_tick.poll_tick(cx).is_ready() {
+            if let Some(rendezvous_peer) = rendezvous_peer_id {
+                if let Some(client) = swarm.behaviour_mut().rendezvous_client.as_mut() {
+                    client.discover(
+                        Some(namespace.clone()),
+                        rendezvous_cookie.clone(),
+                        None,
+                        rendezvous_peer,
+                    );
+                }
+            }
+            continue;
+        }
+        if bandwidth_tick.poll_tick(cx).is_ready() {
+            info!(
+                "Bandwidth: {} bytes in, {} bytes out",
+                bandwidth_sinks.total_inbound(),
+                bandwidth_sinks.total_outbound()
+            );
+            continue;
+        }
         match swarm.poll_next_unpin(cx) {
+            Poll::Ready(Some(SwarmEvent::ConnectionEstablished { peer_id: remote, .. })) => {
+                if Some(remote) == rendezvous_peer_id {
+                    if let Some(client) = swarm.behaviour_mut().rendezvous_client.as_mut() {
+                        client.register(namespace.clone(), remote, None);
+                        client.discover(Some(namespace.clone()), None, None, remote);
+                    }
+                }
+                if let Command::Get { peer, file } = &options.command {
+                    if remote == *peer && download.is_none() {
+                        start_download(&mut swarm, &mut pending_requests, &mut download, peer, file);
+                    }
+                }
+                if let Some((provider, hash)) = &fetch_target {
+                    if remote == *provider && download.is_none() {
+                        start_download(&mut swarm, &mut pending_requests, &mut download, provider, hash);
+                    }
+                }
+            }
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::Kad(
+                libp2p::kad::KademliaEvent::OutboundQueryProgressed {
+                    result: libp2p::kad::QueryResult::GetProviders(result),
+                    ..
+                },
+            )))) => {
+                if let Command::Fetch { hash } = &options.command {
+                    match result {
+                        Ok(libp2p::kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                            if let Some(provider) = providers.into_iter().next() {
+                                info!("Found provider {:?} for {}", provider, hash);
+                                fetch_target = Some((provider, hash.clone()));
+                                if let Err(e) = swarm.dial(provider) {
+                                    warn!("Couldn't dial provider {:?}: {:?}", provider, e);
+                                }
+                                // As with `get`, we may already be connected to the
+                                // provider (e.g. it's also a bootstrap peer), in
+                                // which case `dial` won't trigger a fresh
+                                // `ConnectionEstablished` to start the download from.
+                                if swarm.is_connected(&provider) && download.is_none() {
+                                    start_download(&mut swarm, &mut pending_requests, &mut download, &provider, hash);
+                                }
+                            }
+                        }
+                        Ok(libp2p::kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {
+                            if fetch_target.is_none() {
+                                warn!("No providers found for {}", hash);
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Provider lookup for {} failed: {:?}", hash, e);
+                        }
+                    }
+                }
+            }
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::Kad(event)))) => {
+                info!("Kademlia event: {:?}", event);
+            }
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::FileTransfer(event)))) => {
+                match event {
+                    RequestResponseEvent::Message {
+                        peer,
+                        message:
+                            RequestResponseMessage::Request {
+                                request, channel, ..
+                            },
+                    } => {
+                        if let Command::Serve { dir } = &options.command {
+                            let response =
+                                read_chunk(dir, &hash_index, &request.path_or_hash, request.offset);
+                            let _ = swarm
+                                .behaviour_mut()
+                                .file_transfer
+                                .send_response(channel, response);
+                        } else {
+                            warn!("Ignoring file request from {:?}: not serving", peer);
+                        }
+                    }
+                    RequestResponseEvent::Message {
+                        message:
+                            RequestResponseMessage::Response {
+                                request_id,
+                                response,
+                            },
+                        ..
+                    } => {
+                        pending_requests.remove(&request_id);
+                        if let Some(state) = download.as_mut() {
+                            match response {
+                                FileResponse::Chunk(bytes) => {
+                                    use std::io::Write;
+                                    if let Err(e) = state.out.write_all(&bytes) {
+                                        warn!("Error writing {}: {:?}", state.file, e);
+                                        download = None;
+                                    } else {
+                                        state.offset += bytes.len() as u64;
+                                        let peer = match &options.command {
+                                            Command::Get { peer, .. } => Some(*peer),
+                                            Command::Fetch { .. } => {
+                                                fetch_target.as_ref().map(|(peer, _)| *peer)
+                                            }
+                                            Command::Serve { .. } => None,
+                                        };
+                                        if let Some(peer) = peer {
+                                            let request_id =
+                                                swarm.behaviour_mut().file_transfer.send_request(
+                                                    &peer,
+                                                    FileRequest {
+                                                        path_or_hash: state.file.clone(),
+                                                        offset: state.offset,
+                                                    },
+                                                );
+                                            pending_requests.insert(request_id, ());
+                                        }
+                                    }
+                                }
+                                FileResponse::Done => {
+                                    info!("Finished downloading {}", state.file);
+                                    download = None;
+                                }
+                                FileResponse::NotFound => {
+                                    warn!("Peer doesn't have {}", state.file);
+                                    download = None;
+                                }
+                            }
+                        }
+                    }
+                    other => info!("File transfer event: {:?}", other),
+                }
+            }
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::Autonat(
+                libp2p::autonat::Event::StatusChanged { old, new },
+            )))) => {
+                info!("AutoNAT status changed from {:?} to {:?}", old, new);
+                if new == libp2p::autonat::NatStatus::Private {
+                    if let Some(relay) = &options.relay {
+                        let circuit_addr = relay.clone().with(libp2p::multiaddr::Protocol::P2pCircuit);
+                        if let Err(e) = swarm.listen_on(circuit_addr.clone()) {
+                            warn!("Couldn't listen on relay circuit {:?}: {:?}", circuit_addr, e);
+                        }
+                    }
+                }
+            }
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::Ping(event)))) => {
+                debug!("Ping event: {:?}", event);
+            }
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::RelayClient(event)))) => {
+                info!("Relay client event: {:?}", event);
+            }
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::RelayServer(event)))) => {
+                info!("Relay server event: {:?}", event);
+            }
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)))) => {
+                info!("DCUtR event: {:?}", event);
+            }
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::Mdns(event)))) => match event {
+                libp2p::mdns::Event::Discovered(discovered) => {
+                    for (peer, addr) in discovered {
+                        info!("mDNS discovered {:?} at {:?}", peer, addr);
+                        if let Err(e) = swarm.dial(addr.clone()) {
+                            warn!("Couldn't dial mDNS peer {:?} at {:?}: {:?}", peer, addr, e);
+                        }
+                    }
+                }
+                libp2p::mdns::Event::Expired(expired) => {
+                    for (peer, addr) in expired {
+                        info!("mDNS peer expired: {:?} at {:?}", peer, addr);
+                    }
+                }
+            },
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::RendezvousClient(event)))) => {
+                match event {
+                    libp2p::rendezvous::client::Event::Registered { namespace, .. } => {
+                        info!("Registered with rendezvous point under {:?}", namespace);
+                    }
+                    libp2p::rendezvous::client::Event::Discovered {
+                        registrations,
+                        cookie,
+                        ..
+                    } => {
+                        rendezvous_cookie = Some(cookie);
+                        for registration in registrations {
+                            for addr in registration.record.addresses() {
+                                if let Err(e) = swarm.dial(addr.clone()) {
+                                    warn!("Couldn't dial rendezvous peer at {:?}: {:?}", addr, e);
+                                }
+                            }
+                        }
+                    }
+                    other => info!("Rendezvous client event: {:?}", other),
+                }
+            }
+            Poll::Ready(Some(SwarmEvent::Behaviour(BehaviourEvent::RendezvousServer(event)))) => {
+                info!("Rendezvous server event: {:?}", event);
+            }
             Poll::Ready(Some(event)) => info!("Swarm event: {:?}", event),
             Poll::Ready(None) => return Poll::Ready(()),
             Poll::Pending => return Poll::Pending,
@@ -0,0 +1,210 @@
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::request_response::{Codec, ProtocolName};
+use std::io;
+
+/// Maximum number of bytes we'll read for a single chunk.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The request-response protocol used to fetch files from a peer.
+#[derive(Debug, Clone)]
+pub struct FileExchangeProtocol();
+
+impl ProtocolName for FileExchangeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/p2p-file-transfer/file/1.0.0"
+    }
+}
+
+/// A request for a chunk of a file, identified by the path (or content
+/// hash) under which the serving peer has it available. `offset` lets the
+/// requester pull a file as a sequence of requests, one per chunk, rather
+/// than buffering the whole file on either side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRequest {
+    pub path_or_hash: String,
+    pub offset: u64,
+}
+
+/// A single chunk of a file transfer. `FileResponse::Chunk` messages are
+/// streamed back for a single `FileRequest` until `Done` or `NotFound`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileResponse {
+    Chunk(Vec<u8>),
+    Done,
+    NotFound,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileExchangeCodec();
+
+#[async_trait]
+impl Codec for FileExchangeCodec {
+    type Protocol = FileExchangeProtocol;
+    type Request = FileRequest;
+    type Response = FileResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+    ) -> io::Result<FileRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut offset_buf = [0; 8];
+        io.read_exact(&mut offset_buf).await?;
+        let offset = u64::from_be_bytes(offset_buf);
+
+        let mut len_buf = [0; 4];
+        io.read_exact(&mut len_buf).await?;
+        let len = u32::from_be_bytes(len_buf) as usize;
+        if len > MAX_CHUNK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "path too long"));
+        }
+        let mut buf = vec![0; len];
+        io.read_exact(&mut buf).await?;
+        let path_or_hash = String::from_utf8(buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(FileRequest { path_or_hash, offset })
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+    ) -> io::Result<FileResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut tag = [0; 1];
+        io.read_exact(&mut tag).await?;
+        match tag[0] {
+            0 => {
+                let mut len_buf = [0; 4];
+                io.read_exact(&mut len_buf).await?;
+                let len = u32::from_be_bytes(len_buf) as usize;
+                if len > MAX_CHUNK_SIZE {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk too large"));
+                }
+                let mut buf = vec![0; len];
+                io.read_exact(&mut buf).await?;
+                Ok(FileResponse::Chunk(buf))
+            }
+            1 => Ok(FileResponse::Done),
+            2 => Ok(FileResponse::NotFound),
+            tag => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown FileResponse tag {}", tag),
+            )),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+        FileRequest { path_or_hash, offset }: FileRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = path_or_hash.into_bytes();
+        io.write_all(&offset.to_be_bytes()).await?;
+        io.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+        io.write_all(&bytes).await?;
+        io.close().await?;
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+        response: FileResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match response {
+            FileResponse::Chunk(chunk) => {
+                io.write_all(&[0]).await?;
+                io.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+                io.write_all(&chunk).await?;
+            }
+            FileResponse::Done => io.write_all(&[1]).await?,
+            FileResponse::NotFound => io.write_all(&[2]).await?,
+        }
+        io.close().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+
+    #[test]
+    fn request_round_trips() {
+        futures::executor::block_on(async {
+            let mut codec = FileExchangeCodec::default();
+            let request = FileRequest {
+                path_or_hash: "deadbeef".to_string(),
+                offset: 42,
+            };
+
+            let mut buf = Vec::new();
+            codec
+                .write_request(&FileExchangeProtocol(), &mut buf, request.clone())
+                .await
+                .unwrap();
+
+            let mut cursor = Cursor::new(buf);
+            let decoded = codec
+                .read_request(&FileExchangeProtocol(), &mut cursor)
+                .await
+                .unwrap();
+            assert_eq!(decoded, request);
+        });
+    }
+
+    #[test]
+    fn response_round_trips() {
+        futures::executor::block_on(async {
+            for response in [
+                FileResponse::Chunk(vec![1, 2, 3, 4]),
+                FileResponse::Done,
+                FileResponse::NotFound,
+            ] {
+                let mut codec = FileExchangeCodec::default();
+                let mut buf = Vec::new();
+                codec
+                    .write_response(&FileExchangeProtocol(), &mut buf, response.clone())
+                    .await
+                    .unwrap();
+
+                let mut cursor = Cursor::new(buf);
+                let decoded = codec
+                    .read_response(&FileExchangeProtocol(), &mut cursor)
+                    .await
+                    .unwrap();
+                assert_eq!(decoded, response);
+            }
+        });
+    }
+
+    #[test]
+    fn read_request_rejects_oversized_length_prefix() {
+        futures::executor::block_on(async {
+            let mut codec = FileExchangeCodec::default();
+            let mut buf = Vec::new();
+            buf.extend_from_slice(&0u64.to_be_bytes());
+            buf.extend_from_slice(&(MAX_CHUNK_SIZE as u32 + 1).to_be_bytes());
+
+            let mut cursor = Cursor::new(buf);
+            let result = codec.read_request(&FileExchangeProtocol(), &mut cursor).await;
+            assert!(result.is_err());
+        });
+    }
+}